@@ -1,10 +1,10 @@
 use std::{
-    collections::HashMap, 
-    io::{BufRead, BufReader}, 
+    collections::HashMap,
+    io::{BufRead, BufReader},
 };
 
 #[derive(Clone)]
-struct NativeOp(fn(&mut Vm));
+struct NativeOp(fn(&mut Vm) -> Result<(), VmError>);
 
 // Eq, PartialEq, Debug トレイトを実装する
 impl Eq for NativeOp {}
@@ -19,12 +19,40 @@ impl std::fmt::Debug for NativeOp {
     }
 }
 
+// 実行時に起こりうるエラー。以前はスタック不足や型の取り違えを panic! で表現していたが、
+// スクリプトの1行がクラッシュせず報告できるよう、ここに集約して Result で伝播させる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum VmError {
+    StackUnderflow,
+    TypeMismatch { expected: String, found: String },
+    UndefinedWord(String),
+    DivideByZero,
+    UnbalancedBlock,
+    ModuleNotFound(String),
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StackUnderflow => write!(f, "stack underflow"),
+            Self::TypeMismatch { expected, found } => {
+                write!(f, "expected {} but found {}", expected, found)
+            }
+            Self::UndefinedWord(name) => write!(f, "undefined word `{}`", name),
+            Self::DivideByZero => write!(f, "division by zero"),
+            Self::UnbalancedBlock => write!(f, "unbalanced block (`}}` without matching `{{`)"),
+            Self::ModuleNotFound(path) => write!(f, "module not found: {}", path),
+        }
+    }
+}
+
 macro_rules! impl_op {
     {$name:ident, $op:tt} => {
-        fn $name(vm: &mut Vm) {
-            let rhs = vm.stack.pop().unwrap().as_num();
-            let lhs = vm.stack.pop().unwrap().as_num();
+        fn $name(vm: &mut Vm) -> Result<(), VmError> {
+            let rhs = vm.stack.pop().ok_or(VmError::StackUnderflow)?.as_num()?;
+            let lhs = vm.stack.pop().ok_or(VmError::StackUnderflow)?.as_num()?;
             vm.stack.push(Value::Num((lhs $op rhs) as i32));
+            Ok(())
         }
     }
 }
@@ -32,26 +60,57 @@ macro_rules! impl_op {
 impl_op!(add, +);
 impl_op!(sub, -);
 impl_op!(mul, *);
-impl_op!(div, /);
 impl_op!(lt, <);
 
+// 0除算は impl_op! の一様な式展開では表現できないため、専用に実装する。
+fn div(vm: &mut Vm) -> Result<(), VmError> {
+    let rhs = vm.stack.pop().ok_or(VmError::StackUnderflow)?.as_num()?;
+    let lhs = vm.stack.pop().ok_or(VmError::StackUnderflow)?.as_num()?;
+    if rhs == 0 {
+        return Err(VmError::DivideByZero);
+    }
+    vm.stack.push(Value::Num(lhs / rhs));
+    Ok(())
+}
+
+// フラットなバイトコード命令。コンパイル済みの命令はこの列として vm.code に積まれ、
+// 命令ポインタ (vm.ip) を進めるだけのループで駆動される。
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Instr {
+    Push(Value),
+    LoadVar(String),
+    StoreVar(String),
+    BinOp(NativeOp),
+    Native(NativeOp),
+    Call(usize),
+    Ret,
+    Jump(usize),
+    JumpUnless(usize),
+}
+
 // 仮想マシンの構造体を定義
 #[derive(Debug, Clone)]
 struct Vm {
     stack: Vec<Value>,            // スタックを保持するベクタ
     vars: HashMap<String, Value>, // 変数を保持するハッシュマップ
     blocks: Vec<Vec<Value>>,      // ブロックを保持するベクタ
+    code: Vec<Instr>,             // これまでにコンパイルされた命令列（末尾にのみ追記する）
+    ip: usize,                    // 次に実行する命令の位置
+    call_stack: Vec<usize>,       // Call の戻り先アドレスを積むスタック
+    functions: HashMap<String, usize>, // def で定義された関数（ブロック）の code 上の開始アドレス
+    next_loop_id: usize,          // for のカウンタ変数名を一意にするための連番
+    base_dir: std::path::PathBuf, // require の相対パスをここからの相対として解決する（現在読み込み中のファイルのディレクトリ）
+    loaded_modules: std::collections::HashSet<std::path::PathBuf>, // 二重ロード・循環importを防ぐため読み込み済みの正規化パスを記録する
 }
 
 impl Vm {
     fn new() -> Self {
-        let functions: [(&str, fn(&mut Vm)); 10] = [
+        let functions: [(&str, fn(&mut Vm) -> Result<(), VmError>); 9] = [
             ("+", add),
             ("-", sub),
             ("*", mul),
             ("/", div),
             ("<", lt),
-            ("if", op_if),
             ("def", op_def),
             ("puts", puts),
             ("dup", dup),
@@ -64,6 +123,13 @@ impl Vm {
                     (name.to_owned(), Value::Native(NativeOp(fun)))
                 }).collect(),
             blocks: vec![],
+            code: vec![],
+            ip: 0,
+            call_stack: vec![],
+            functions: HashMap::new(),
+            next_loop_id: 0,
+            base_dir: std::path::PathBuf::from("."),
+            loaded_modules: std::collections::HashSet::new(),
         }
     }
 }
@@ -73,197 +139,1179 @@ enum Value {
     Num(i32),
     Op(String),
     Sym(String),
+    Str(String),
     Block(Vec<Value>),
     Native(NativeOp),
+    // `${ 式 }` を含む可能性がある backtick 文字列リテラルの未コンパイル表現。
+    // コンパイル時に compile_interpolation でリテラル片と式を cat で結合する命令列に展開される。
+    Interp(String),
 }
 
 impl Value {
-    fn as_num(&self) -> i32 {
+    fn as_num(&self) -> Result<i32, VmError> {
         match self {
-            Self::Num(val) => *val,
-            _ => panic!("Value is not a number"),
+            Self::Num(val) => Ok(*val),
+            other => Err(VmError::TypeMismatch {
+                expected: "Num".to_string(),
+                found: format!("{:?}", other),
+            }),
         }
     }
-    fn to_block(self) -> Vec<Value> {
+    fn to_block(self) -> Result<Vec<Value>, VmError> {
         match self {
-            Self::Block(val) => val,
-            _ => panic!("Value is not a block"),
+            Self::Block(val) => Ok(val),
+            other => Err(VmError::TypeMismatch {
+                expected: "Block".to_string(),
+                found: format!("{:?}", other),
+            }),
         }
     }
-    fn as_sym(&self) -> &str {
+    fn as_sym(&self) -> Result<&str, VmError> {
         if let Self::Sym(sym) = self {
-            sym
+            Ok(sym)
         } else {
-            panic!("Value is not a symbol");
+            Err(VmError::TypeMismatch {
+                expected: "Sym".to_string(),
+                found: format!("{:?}", self),
+            })
+        }
+    }
+    fn as_str(&self) -> Result<&str, VmError> {
+        if let Self::Str(s) = self {
+            Ok(s)
+        } else {
+            Err(VmError::TypeMismatch {
+                expected: "Str".to_string(),
+                found: format!("{:?}", self),
+            })
         }
     }
     fn to_string(&self) -> String {
         match self {
             Self::Num(i) => i.to_string(),
-            Self::Op(ref s) | Self::Sym(ref s) => s.clone(),
+            Self::Op(ref s) | Self::Sym(ref s) | Self::Str(ref s) => s.clone(),
             Self::Block(_) => "<Block>".to_string(),
             Self::Native(_) => "<Native>".to_string(),
+            Self::Interp(_) => "<Interp>".to_string(),
         }
     }
 }
 
 fn main() {
-    if let Some(f) = std::env::args().nth(1).and_then(|f| std::fs::File::open(f).ok()) {
-        parse_batch(BufReader::new(f));
-    } else {
-        parse_interactive();
+    if let Some(arg) = std::env::args().nth(1) {
+        let path = std::path::Path::new(&arg);
+        if path.exists() {
+            if let Err(err) = parse_file(path) {
+                eprintln!("error: {}", err);
+            }
+            return;
+        }
     }
+    parse_interactive();
 }
 
-fn parse_batch(source: impl BufRead) -> Vec<Value> {
+// バッチ実行では、スクリプトの1語でも VmError を起こした時点でそれ以上続けても
+// 意味のある結果にならないため、そこで打ち切って呼び出し元に伝える。
+// ファイル実行は require の相対パス解決のため base_dir を自前で設定する必要がある
+// parse_file 経由で行うので、メモリ上のソースをそのまま実行したいテストからのみ使う。
+#[cfg(test)]
+fn parse_batch(source: impl BufRead) -> Result<Vec<Value>, VmError> {
+    let mut vm = Vm::new();
+    run_lines(source, &mut vm)?;
+    Ok(vm.stack)
+}
+
+// ファイルを実行する。require の相対パス解決はこのファイル自身のディレクトリを
+// 基準にしたいので、Vm::new() の後に base_dir を差し替えてから走らせる。
+// 自分自身を循環 require しても無限ループしないよう、開始時点で正規化パスを
+// 読み込み済みとして登録しておく。
+fn parse_file(path: &std::path::Path) -> Result<Vec<Value>, VmError> {
     let mut vm = Vm::new();
+    vm.base_dir = path
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_else(|| std::path::PathBuf::from("."));
+    if let Ok(canonical) = path.canonicalize() {
+        vm.loaded_modules.insert(canonical);
+    }
+
+    let f = std::fs::File::open(path).map_err(|_| VmError::ModuleNotFound(path.display().to_string()))?;
+    run_lines(BufReader::new(f), &mut vm)?;
+    Ok(vm.stack)
+}
+
+// ソースの各行・各トークンを現在の Vm に対して評価する。parse_batch / parse_file /
+// require がいずれもこのループを共有し、どの Vm（どの base_dir）に対して走らせるかだけが異なる。
+fn run_lines(source: impl BufRead, vm: &mut Vm) -> Result<(), VmError> {
     for line in source.lines().flatten() {
-        for word in line.split(" ") {
+        for word in tokenize(&line) {
             let vm_before = vm.clone();
-            parse_word(word, &mut vm);
-            debug_vm_diff(word, &vm_before, &vm);
+            parse_word(&word, vm)?;
+            debug_vm_diff(&word, &vm_before, vm);
         }
     }
-    vm.stack
+    Ok(())
 }
 
+// 対話実行では、1語の VmError でセッション全体を終わらせたくないので、
+// エラーを表示してその行の残りの入力を受け付け続ける。
 fn parse_interactive() {
     let mut vm = Vm::new();
     for line in std::io::stdin().lines().flatten() {
-        for word in line.split(" ") {
-            parse_word(word, &mut vm);
+        for word in tokenize(&line) {
+            if let Err(err) = parse_word(&word, &mut vm) {
+                eprintln!("error: {}", err);
+            }
         }
         println!("stack: {:?}", vm.stack);
     }
 }
 
-fn parse_word(word: &str, vm: &mut Vm) {
+// 行を空白区切りのトークンに分割する。ただし `"..."` と `` `...` `` で囲まれた区間は、
+// 中に空白が含まれていても1つのトークンとしてまとめる（引用符自体もトークンに残す）。
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        if c == '"' || c == '`' {
+            current.push(c);
+            for c2 in chars.by_ref() {
+                current.push(c2);
+                if c2 == c {
+                    break;
+                }
+            }
+            tokens.push(std::mem::take(&mut current));
+            continue;
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn parse_word(word: &str, vm: &mut Vm) -> Result<(), VmError> {
     if word.is_empty() {
-        return;
+        return Ok(());
     }
     if word == "{" {
         // ブロックを保持できるように、blocksに空のベクタを追加する
         vm.blocks.push(vec![]);
-        return;
+        return Ok(());
     }
     if word == "}" {
         // ブロックを保持するベクタを取り出し、Blockとしてスタックに積む
-        let block = vm.blocks.pop().expect("block stack is empty");
-        eval(Value::Block(block), vm);
-        return;
+        let block = vm.blocks.pop().ok_or(VmError::UnbalancedBlock)?;
+        eval(Value::Block(block), vm)?;
+        return Ok(());
     }
     if word == "\u{3000}" {
-        return;
+        return Ok(());
     }
-    // 値の種類によって、Value のインスタンスを生成しcodeに保持する
-    let code = if let Ok(num) = word.parse::<i32>() {
+    eval(token_to_value(word), vm)
+}
+
+// 数字・シンボル・文字列リテラル・演算子のいずれであるかを見てトークンを Value に変換する。
+// parse_word と、`${ }` の中身（埋め込み式）をコンパイルする際の両方から使う。
+fn token_to_value(word: &str) -> Value {
+    if let Ok(num) = word.parse::<i32>() {
         // 数字の場合は、Num としてスタックに積む
         Value::Num(num)
-    } else if word.starts_with("/") {
-        Value::Sym(word[1..].to_string()) // /から始まる文字列を変数名とするため、/を取り除いた文字列を保持する
+    } else if let Some(sym) = word.strip_prefix("/") {
+        Value::Sym(sym.to_string()) // /から始まる文字列を変数名とするため、/を取り除いた文字列を保持する
+    } else if word.len() >= 2 && word.starts_with('"') && word.ends_with('"') {
+        Value::Str(word[1..word.len() - 1].to_string())
+    } else if word.len() >= 2 && word.starts_with('`') && word.ends_with('`') {
+        Value::Interp(word[1..word.len() - 1].to_string())
     } else {
-        // 数字、{} 以外の場合、演算子として処理する
+        // 数字、{} 、文字列リテラル以外の場合、演算子として処理する
         Value::Op(word.to_string())
-    };
-    eval(code, vm);
+    }
 }
 
-fn eval(code: Value, vm: &mut Vm) {
-    println!("--------------------------------");
-    println!("eval: {:?}\nStack: {:?} \n", code, vm.stack);
-    for (key,value) in vm.vars.iter() {
-        if matches!(value, Value::Native(_)) {
-            continue;
-        }
-        println!("{}: {:?}", key, value);
-    }
+// 1トークン分の Value を評価する。ブロックの中では従来通り単に積むだけだが、トップレベルでは
+// まず静的な型検査を行い、それを通過した場合にのみバイトコードへコンパイルして
+// vm.code の末尾に追記し、その場で実行する。型検査のエラーはここで報告して打ち切るだけだが、
+// 実行時エラー (VmError) は呼び出し元まで伝播させる。
+fn eval(code: Value, vm: &mut Vm) -> Result<(), VmError> {
     // ブロック構造の中にある場合、評価せずにブロックにコードを追加する
     if let Some(top_block) = vm.blocks.last_mut() {
         top_block.push(code);
-        return;
+        return Ok(());
+    }
+
+    if let Err(err) = check_top_level(&code, vm) {
+        eprintln!("type error: {}", err);
+        return Ok(());
     }
-    // 演算子でない場合はスタックに積む
-    if !matches!(code, Value::Op(_)) {
-        vm.stack.push(code);
-        return;
+
+    let instrs = compile_top_level(code, vm)?;
+    run_appended(instrs, vm)
+}
+
+// トークン1個分の命令列を vm.code の末尾に追記し、その範囲だけを実行する。
+// build_if などが生成する Jump/JumpUnless はチャンク先頭からの相対アドレスなので、
+// 追記先の絶対位置 (base) を足してから vm.code に積む。Call の飛び先は def の時点で
+// 既に絶対アドレスとして確定しているのでそのまま通す。
+// def が関数本体を code のさらに後方に追記しても、ここで回すのはあくまで追記前に
+// 決めた範囲 (stop_at) までで、関数本体は Call 経由でのみ実行される。
+fn run_appended(instrs: Vec<Instr>, vm: &mut Vm) -> Result<(), VmError> {
+    // op_def は呼び出し元の run_appended とは別に関数本体を vm.code の末尾に追記するため、
+    // ip を使い回さずここで明示的に追記位置へ合わせてから実行する。
+    vm.ip = vm.code.len();
+    append_chunk(&mut vm.code, instrs);
+    let stop_at = vm.code.len();
+    while vm.ip < stop_at {
+        step(vm)?;
     }
+    Ok(())
+}
 
-    // 演算子の場合
-    let Value::Op(op) = code else {
-        panic!("Expected operator, found {:?}", code);
-    };
+// Jump/JumpUnless の相対アドレスを、チャンクの追記先である base を足した絶対アドレスに直す。
+fn relocate(instr: Instr, base: usize) -> Instr {
+    match instr {
+        Instr::Jump(addr) => Instr::Jump(addr + base),
+        Instr::JumpUnless(addr) => Instr::JumpUnless(addr + base),
+        other => other,
+    }
+}
+
+// chunk はそれ自身の先頭 (0) を基準にした相対アドレスで Jump/JumpUnless を持っている可能性が
+// あるので、dst への追記位置 (dst.len()) を base として relocate してから積む。
+fn append_chunk(dst: &mut Vec<Instr>, chunk: Vec<Instr>) {
+    let base = dst.len();
+    dst.extend(chunk.into_iter().map(|instr| relocate(instr, base)));
+}
+
+// 命令ポインタ駆動のバイトコードインタプリタ本体。while ループだけで駆動されるため、
+// def で定義した関数を通じた再帰呼び出しもネイティブスタックを消費しない。
+// VmError は呼び出し元の run_appended まで伝播し、その時点で実行を打ち切る。
+fn step(vm: &mut Vm) -> Result<(), VmError> {
+    match vm.code[vm.ip].clone() {
+        Instr::Push(val) => {
+            vm.stack.push(val);
+            vm.ip += 1;
+        }
+        Instr::LoadVar(name) => {
+            let val = vm
+                .vars
+                .get(&name)
+                .cloned()
+                .ok_or_else(|| VmError::UndefinedWord(name.clone()))?;
+            vm.stack.push(val);
+            vm.ip += 1;
+        }
+        Instr::StoreVar(name) => {
+            let val = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+            vm.vars.insert(name, val);
+            vm.ip += 1;
+        }
+        Instr::BinOp(op) | Instr::Native(op) => {
+            // require のようにネイティブ演算の内部で run_appended を再帰的に回すものは、
+            // その場で vm.ip を使い回して進めてしまう。呼び出し前の次命令位置を
+            // 覚えておき、戻ってきたら無条件にそこへ戻すことで、呼び出し元の
+            // run_appended が期待する ip に復元する。
+            let next_ip = vm.ip + 1;
+            op.0(vm)?;
+            vm.ip = next_ip;
+        }
+        Instr::Call(addr) => {
+            vm.call_stack.push(vm.ip + 1);
+            vm.ip = addr;
+        }
+        Instr::Ret => {
+            vm.ip = vm.call_stack.pop().ok_or(VmError::StackUnderflow)?;
+        }
+        Instr::Jump(addr) => {
+            vm.ip = addr;
+        }
+        Instr::JumpUnless(addr) => {
+            let cond = vm.stack.pop().ok_or(VmError::StackUnderflow)?.as_num()?;
+            if cond != 0 {
+                vm.ip += 1;
+            } else {
+                vm.ip = addr;
+            }
+        }
+    }
+    Ok(())
+}
+
+// トップレベルの1トークンをコンパイルする。`if` は直前の3ブロックがすでに評価済みで
+// スタックに載っていることを前提に、その場でスタックから取り出してインライン展開する。
+fn compile_top_level(value: Value, vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    match value {
+        Value::Op(name) => compile_op(&name, vm),
+        Value::Interp(text) => compile_interpolation(&text, vm),
+        other => Ok(vec![Instr::Push(other)]),
+    }
+}
+
+fn compile_op(name: &str, vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    Ok(match name {
+        "+" => vec![Instr::BinOp(NativeOp(add))],
+        "-" => vec![Instr::BinOp(NativeOp(sub))],
+        "*" => vec![Instr::BinOp(NativeOp(mul))],
+        "/" => vec![Instr::BinOp(NativeOp(div))],
+        "<" => vec![Instr::BinOp(NativeOp(lt))],
+        "dup" => vec![Instr::Native(NativeOp(dup))],
+        "exch" => vec![Instr::Native(NativeOp(exch))],
+        "puts" => vec![Instr::Native(NativeOp(puts))],
+        "cat" => vec![Instr::Native(NativeOp(cat))],
+        "len" => vec![Instr::Native(NativeOp(str_len))],
+        "str" => vec![Instr::Native(NativeOp(to_str))],
+        "require" | "import" => vec![Instr::Native(NativeOp(require))],
+        "if" => return compile_if_top_level(vm),
+        "while" => return compile_while_top_level(vm),
+        "for" => return compile_for_top_level(vm),
+        "switch" => return compile_switch_top_level(vm),
+        "def" => vec![Instr::Native(NativeOp(op_def))],
+        _ => {
+            if let Some(&addr) = vm.functions.get(name) {
+                vec![Instr::Call(addr)]
+            } else {
+                vec![Instr::LoadVar(name.to_string())]
+            }
+        }
+    })
+}
+
+// `{ cond } { true } { false } if` の3ブロックはすでにスタックに積まれているので、
+// ここで取り出して build_if に渡す。
+fn compile_if_top_level(vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let false_branch = vm.stack.pop().ok_or(VmError::StackUnderflow)?.to_block()?;
+    let true_branch = vm.stack.pop().ok_or(VmError::StackUnderflow)?.to_block()?;
+    let cond = vm.stack.pop().ok_or(VmError::StackUnderflow)?.to_block()?;
+    build_if(cond, true_branch, false_branch, vm)
+}
+
+// cond / true-branch / false-branch を1本の命令列に結合し、前方ジャンプの飛び先を
+// バックパッチで解決する。条件が0以外ならtrue-branch、0ならfalse-branchを実行する。
+fn build_if(cond: Vec<Value>, true_branch: Vec<Value>, false_branch: Vec<Value>, vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let mut instrs = Vec::new();
+    append_chunk(&mut instrs, compile_block(&cond, vm)?);
+
+    let jump_unless_at = instrs.len();
+    instrs.push(Instr::JumpUnless(0)); // 飛び先は後で埋める
+
+    append_chunk(&mut instrs, compile_block(&true_branch, vm)?);
+    let jump_at = instrs.len();
+    instrs.push(Instr::Jump(0)); // 飛び先は後で埋める
+
+    let else_start = instrs.len();
+    append_chunk(&mut instrs, compile_block(&false_branch, vm)?);
+    let end = instrs.len();
+
+    instrs[jump_unless_at] = Instr::JumpUnless(else_start);
+    instrs[jump_at] = Instr::Jump(end);
+    Ok(instrs)
+}
+
+// `{ cond } { body } while` の2ブロックはすでにスタックに積まれているので、
+// ここで取り出して build_while に渡す。
+fn compile_while_top_level(vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let body = vm.stack.pop().ok_or(VmError::StackUnderflow)?.to_block()?;
+    let cond = vm.stack.pop().ok_or(VmError::StackUnderflow)?.to_block()?;
+    build_while(cond, body, vm)
+}
+
+// ループ先頭で cond を評価し、0 なら JumpUnless でループの外へ抜ける。0 以外なら body を
+// 実行してから先頭へ Jump で戻る。ループ外へ抜ける前方 JumpUnless の飛び先は if と同じ
+// バックパッチで埋め、先頭へ戻る後方 Jump は飛び先が既知なのでそのまま積む。
+fn build_while(cond: Vec<Value>, body: Vec<Value>, vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let mut instrs = Vec::new();
+    let loop_top = 0;
+    append_chunk(&mut instrs, compile_block(&cond, vm)?);
 
-    // op_defで定義された変数がある場合は、その値を取得する
-    if let Some(val) = vm.vars.get(&op).cloned() {
-        match val {
-            Value::Block(block) => {
-                // ブロックの中身を評価
-                for code in block {
-                    eval(code, vm);
+    let jump_unless_at = instrs.len();
+    instrs.push(Instr::JumpUnless(0)); // 飛び先は後で埋める
+
+    append_chunk(&mut instrs, compile_block(&body, vm)?);
+    instrs.push(Instr::Jump(loop_top));
+
+    let end = instrs.len();
+    instrs[jump_unless_at] = Instr::JumpUnless(end);
+    Ok(instrs)
+}
+
+// `start end { body } for` はすでにスタックに積まれているので、ここで取り出して
+// build_for に渡す。start/end はこの時点ですでに評価済みの具体的な数値である前提。
+fn compile_for_top_level(vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let body = vm.stack.pop().ok_or(VmError::StackUnderflow)?.to_block()?;
+    let end = vm.stack.pop().ok_or(VmError::StackUnderflow)?.as_num()?;
+    let start = vm.stack.pop().ok_or(VmError::StackUnderflow)?.as_num()?;
+    build_for(vec![Value::Num(start)], vec![Value::Num(end)], body, vm)
+}
+
+// start / end はリテラルとは限らず、変数参照など実行時に評価が必要な式かもしれないので、
+// トップレベルの cond などと同じく未評価のトークン列として受け取り、ループ先頭で1回だけ
+// 評価してカウンタ・上限の各変数に格納する（毎周再評価はしない）。カウンタは
+// StoreVar/LoadVar でループ専用の変数に保持し、body の実行前にスタックへ積む。
+// 変数名はネストした for 同士が衝突しないよう呼び出しごとに採番する。
+fn build_for(start: Vec<Value>, end: Vec<Value>, body: Vec<Value>, vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let counter = format!("__for_idx_{}__", vm.next_loop_id);
+    let bound = format!("__for_end_{}__", vm.next_loop_id);
+    vm.next_loop_id += 1;
+
+    let mut instrs = Vec::new();
+    append_chunk(&mut instrs, compile_block(&start, vm)?);
+    instrs.push(Instr::StoreVar(counter.clone()));
+    append_chunk(&mut instrs, compile_block(&end, vm)?);
+    instrs.push(Instr::StoreVar(bound.clone()));
+
+    let loop_top = instrs.len();
+    instrs.push(Instr::LoadVar(counter.clone()));
+    instrs.push(Instr::LoadVar(bound.clone()));
+    instrs.push(Instr::BinOp(NativeOp(lt)));
+    let jump_unless_at = instrs.len();
+    instrs.push(Instr::JumpUnless(0)); // 飛び先は後で埋める
+
+    instrs.push(Instr::LoadVar(counter.clone())); // body 実行前に現在の添字を積む
+    append_chunk(&mut instrs, compile_block(&body, vm)?);
+
+    instrs.push(Instr::LoadVar(counter.clone()));
+    instrs.push(Instr::Push(Value::Num(1)));
+    instrs.push(Instr::BinOp(NativeOp(add)));
+    instrs.push(Instr::StoreVar(counter));
+    instrs.push(Instr::Jump(loop_top));
+
+    let end_addr = instrs.len();
+    instrs[jump_unless_at] = Instr::JumpUnless(end_addr);
+    Ok(instrs)
+}
+
+// `{ {cond0} {body0} {cond1} {body1} {default} } switch` の1ブロックはすでに
+// スタックに積まれているので、ここで取り出して build_switch に渡す。
+fn compile_switch_top_level(vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let cases = vm.stack.pop().ok_or(VmError::StackUnderflow)?.to_block()?;
+    build_switch(cases, vm)
+}
+
+// switch の cases は cond/body の対の後に default が1つ続くので、常に奇数個でなければ
+// ならない。build_switch (命令列生成) と check_switch (型検査) の両方がこの検証とペア数の
+// 算出を必要とするので、ここに一本化して食い違いが起きないようにする。
+fn switch_pair_count(cases_len: usize) -> Option<usize> {
+    if cases_len == 0 || cases_len % 2 == 0 {
+        None
+    } else {
+        Some((cases_len - 1) / 2)
+    }
+}
+
+// cond/body の対を先頭から順に評価し、最初に真 (0以外) になった cond の body を実行して
+// switch 全体を抜ける。末尾は条件を持たない default で、どの cond も真にならなければこれが
+// 実行される。default は最後の要素でなければならない（奇数個でなければエラー）。
+fn build_switch(cases: Vec<Value>, vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let pair_count = switch_pair_count(cases.len()).ok_or_else(|| VmError::TypeMismatch {
+        expected: "an odd number of blocks (cond/body pairs followed by a trailing default)".to_string(),
+        found: format!("{} block(s)", cases.len()),
+    })?;
+
+    let mut instrs = Vec::new();
+    let mut jump_to_end = Vec::new();
+    for i in 0..pair_count {
+        let cond = cases[i * 2].clone().to_block()?;
+        let body = cases[i * 2 + 1].clone().to_block()?;
+
+        append_chunk(&mut instrs, compile_block(&cond, vm)?);
+        let jump_unless_at = instrs.len();
+        instrs.push(Instr::JumpUnless(0)); // 飛び先は後で埋める
+
+        append_chunk(&mut instrs, compile_block(&body, vm)?);
+        let jump_at = instrs.len();
+        instrs.push(Instr::Jump(0)); // 飛び先は後で埋める
+        jump_to_end.push(jump_at);
+
+        let next_case = instrs.len();
+        instrs[jump_unless_at] = Instr::JumpUnless(next_case);
+    }
+
+    let default = cases[cases.len() - 1].clone().to_block()?;
+    append_chunk(&mut instrs, compile_block(&default, vm)?);
+
+    let end = instrs.len();
+    for jump_at in jump_to_end {
+        instrs[jump_at] = Instr::Jump(end);
+    }
+    Ok(instrs)
+}
+
+// backtick 文字列リテラルの中身をリテラル片と `${ 式 }` に分割したもの。
+enum InterpSegment {
+    Literal(String),
+    Expr(String),
+}
+
+// `foo${ 1 2 + }bar` のような文字列を、リテラル片と埋め込み式に分割する。
+// `${` から対応する `}` までを式として切り出すため、式の中に `{ }` ブロックを
+// 書いてもネストを数えて対応する閉じ括弧まで読み進める。
+fn split_interpolation(text: &str) -> Vec<InterpSegment> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '$' && chars.peek() == Some(&'{') {
+            chars.next(); // '{' を読み飛ばす
+            if !literal.is_empty() {
+                segments.push(InterpSegment::Literal(std::mem::take(&mut literal)));
+            }
+            let mut expr = String::new();
+            let mut depth = 1;
+            for c2 in chars.by_ref() {
+                match c2 {
+                    '{' => depth += 1,
+                    '}' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    _ => {}
                 }
-            },
-            Value::Native(op) => op.0(vm), // ネイティブ関数の場合は実行
-            _ => {
-                vm.stack.push(val); // 他の値はスタックに積む
+                expr.push(c2);
             }
+            segments.push(InterpSegment::Expr(expr));
+        } else {
+            literal.push(c);
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(InterpSegment::Literal(literal));
+    }
+    segments
+}
+
+// backtick 文字列を、リテラル片の Push(Str) と、埋め込み式を評価して str で文字列化した
+// ものを cat で順につなげていく命令列にコンパイルする。
+fn compile_interpolation(text: &str, vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let mut instrs: Vec<Instr> = Vec::new();
+    for segment in split_interpolation(text) {
+        let segment_instrs = match segment {
+            InterpSegment::Literal(s) => vec![Instr::Push(Value::Str(s))],
+            InterpSegment::Expr(src) => compile_embedded_expr(&src, vm)?,
+        };
+        if instrs.is_empty() {
+            append_chunk(&mut instrs, segment_instrs);
+        } else {
+            append_chunk(&mut instrs, segment_instrs);
+            instrs.push(Instr::Native(NativeOp(cat)));
+        }
+    }
+    if instrs.is_empty() {
+        instrs.push(Instr::Push(Value::Str(String::new())));
+    }
+    Ok(instrs)
+}
+
+// `${ }` の中身をトークン化してコンパイルし、最後に str で結果を文字列へ変換する。
+fn compile_embedded_expr(src: &str, vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let mut instrs = Vec::new();
+    for word in tokenize(src) {
+        append_chunk(&mut instrs, compile_top_level(token_to_value(&word), vm)?);
+    }
+    instrs.push(Instr::Native(NativeOp(to_str)));
+    Ok(instrs)
+}
+
+// if/while/for/switch はいずれも「直前に並ぶ N 個の Block + それに続く対応する Op」という
+// 形で認識される (N は if=3, while=2, for=3, switch=1)。compile_block（命令列生成）と
+// check_block（型検査）の両方がこの形状判定を必要とし、どちらか一方だけ更新されて食い違う
+// と一方の構文が通らなくなったり誤った型検査結果になったりする（実際に for の追加時にこれで
+// 両者がずれた）。判定だけをここに一本化し、各 Block の取り出し方・エラー型は
+// compile_block/check_block それぞれに委ねる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlShape {
+    If,
+    While,
+    For,
+    Switch,
+}
+
+impl ControlShape {
+    // 末尾の Op を除く、先頭から連続して並ぶ Block の個数。
+    fn block_count(self) -> usize {
+        match self {
+            ControlShape::If => 3,
+            ControlShape::While => 2,
+            ControlShape::For => 3,
+            ControlShape::Switch => 1,
+        }
+    }
+
+    fn op_name(self) -> &'static str {
+        match self {
+            ControlShape::If => "if",
+            ControlShape::While => "while",
+            ControlShape::For => "for",
+            ControlShape::Switch => "switch",
+        }
+    }
+}
+
+// items[i..] が何らかの ControlShape に一致するか調べる。一致すれば (形状, 末尾 Op の
+// 次のインデックス) を返す。
+fn match_control_shape(items: &[Value], i: usize) -> Option<(ControlShape, usize)> {
+    for shape in [ControlShape::If, ControlShape::While, ControlShape::For, ControlShape::Switch] {
+        let n = shape.block_count();
+        if i + n < items.len()
+            && items[i..i + n].iter().all(|v| matches!(v, Value::Block(_)))
+            && matches!(&items[i + n], Value::Op(op) if op == shape.op_name())
+        {
+            return Some((shape, i + n + 1));
+        }
+    }
+    None
+}
+
+// ブロック本体（def の関数本体やif の各分岐）をコンパイルする。こちらは実行前の静的な
+// トークン列が相手なので、制御構造の各 Block は（スタックではなく）このトークン列自体を
+// 先読みして取り出す。
+fn compile_block(items: &[Value], vm: &mut Vm) -> Result<Vec<Instr>, VmError> {
+    let mut instrs = Vec::new();
+    let mut i = 0;
+    while i < items.len() {
+        if let Some((shape, next)) = match_control_shape(items, i) {
+            let shape_instrs = match shape {
+                ControlShape::If => {
+                    let cond = items[i].clone().to_block()?;
+                    let true_branch = items[i + 1].clone().to_block()?;
+                    let false_branch = items[i + 2].clone().to_block()?;
+                    build_if(cond, true_branch, false_branch, vm)?
+                }
+                ControlShape::While => {
+                    let cond = items[i].clone().to_block()?;
+                    let body = items[i + 1].clone().to_block()?;
+                    build_while(cond, body, vm)?
+                }
+                ControlShape::For => {
+                    let start = items[i].clone().to_block()?;
+                    let end = items[i + 1].clone().to_block()?;
+                    let body = items[i + 2].clone().to_block()?;
+                    build_for(start, end, body, vm)?
+                }
+                ControlShape::Switch => {
+                    let cases = items[i].clone().to_block()?;
+                    build_switch(cases, vm)?
+                }
+            };
+            append_chunk(&mut instrs, shape_instrs);
+            i = next;
+            continue;
+        }
+        append_chunk(&mut instrs, compile_top_level(items[i].clone(), vm)?);
+        i += 1;
+    }
+    Ok(instrs)
+}
+
+// ===== 静的型検査 (実行前にスタックの深さと値の種類を検証する) =====
+
+// 抽象的なスタック上の値の「種類」。ユーザ定義ワード（関数・変数）は呼び出し時の
+// 実際の効果が分からないため Unknown として扱い、他のどの種類とも整合するとみなす。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Num,
+    Block,
+    Sym,
+    Str,
+    Unknown,
+}
+
+impl Kind {
+    fn from_value(value: &Value) -> Kind {
+        match value {
+            Value::Num(_) => Kind::Num,
+            Value::Sym(_) => Kind::Sym,
+            Value::Str(_) | Value::Interp(_) => Kind::Str,
+            Value::Block(_) => Kind::Block,
+            Value::Op(_) | Value::Native(_) => Kind::Unknown,
         }
-        return;
     }
 }
 
-// if演算子を定義する関数
-fn op_if(vm: &mut Vm) {
-    let false_branch = vm.stack.pop().unwrap().to_block();
-    let true_branch = vm.stack.pop().unwrap().to_block();
-    let cond = vm.stack.pop().unwrap().to_block();
+// 型検査で見つかった最初の不整合。演算子名と期待/実際の種類を保持する構造化エラー。
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TypeError {
+    operator: String,
+    expected: String,
+    found: String,
+}
 
-    // 条件式の評価を行う
-    for code in cond {
-        eval(code, vm);
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` expects {} but found {}", self.operator, self.expected, self.found)
     }
+}
 
-    // 条件式の評価結果を取得する
-    let cond_result = vm.stack.pop().unwrap().as_num();
+fn expect_kind(kind: Kind, expected: Kind, operator: &str) -> Result<(), TypeError> {
+    if kind == expected || kind == Kind::Unknown || expected == Kind::Unknown {
+        Ok(())
+    } else {
+        Err(TypeError {
+            operator: operator.to_string(),
+            expected: format!("{:?}", expected),
+            found: format!("{:?}", kind),
+        })
+    }
+}
 
-    // 条件式の結果によって、true_branch か false_branch を評価する
-    if cond_result != 0 {
-        for code in true_branch {
-            eval(code, vm);
+// 2つの抽象スタックが合流可能か調べる。深さが等しく、各位置の種類が一致するか
+// どちらかが Unknown であれば整合するとみなす。
+fn shapes_compatible(a: &[Kind], b: &[Kind]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(x, y)| x == y || *x == Kind::Unknown || *y == Kind::Unknown)
+}
+
+fn pop_kind(stack: &mut Vec<Kind>, operator: &str, expected: &str) -> Result<Kind, TypeError> {
+    stack.pop().ok_or_else(|| TypeError {
+        operator: operator.to_string(),
+        expected: expected.to_string(),
+        found: "an empty stack".to_string(),
+    })
+}
+
+fn expect_block<'a>(value: &'a Value, operator: &str) -> Result<&'a Vec<Value>, TypeError> {
+    if let Value::Block(items) = value {
+        Ok(items)
+    } else {
+        Err(TypeError {
+            operator: operator.to_string(),
+            expected: "Block".to_string(),
+            found: format!("{:?}", Kind::from_value(value)),
+        })
+    }
+}
+
+// 組み込み演算子1つぶんのスタック効果を検証する。if/while/for/switch は複数の
+// ブロックにまたがる構造を持つため check_block / 各 check_* 関数で個別に扱う。
+// 未知のワード（ユーザ定義関数・変数）は効果が分からないので Unknown を積むだけにする。
+fn check_op(name: &str, stack: &mut Vec<Kind>) -> Result<(), TypeError> {
+    match name {
+        "+" | "-" | "*" | "/" | "<" => {
+            let rhs = pop_kind(stack, name, "Num")?;
+            expect_kind(rhs, Kind::Num, name)?;
+            let lhs = pop_kind(stack, name, "Num")?;
+            expect_kind(lhs, Kind::Num, name)?;
+            stack.push(Kind::Num);
+            Ok(())
+        }
+        "dup" => {
+            let top = *stack.last().ok_or_else(|| TypeError {
+                operator: "dup".to_string(),
+                expected: "a value".to_string(),
+                found: "an empty stack".to_string(),
+            })?;
+            stack.push(top);
+            Ok(())
+        }
+        "exch" => {
+            if stack.len() < 2 {
+                return Err(TypeError {
+                    operator: "exch".to_string(),
+                    expected: "two values".to_string(),
+                    found: format!("{} value(s)", stack.len()),
+                });
+            }
+            let len = stack.len();
+            stack.swap(len - 1, len - 2);
+            Ok(())
         }
+        "puts" => {
+            pop_kind(stack, "puts", "a value")?;
+            Ok(())
+        }
+        "cat" => {
+            let rhs = pop_kind(stack, "cat", "Str")?;
+            expect_kind(rhs, Kind::Str, "cat")?;
+            let lhs = pop_kind(stack, "cat", "Str")?;
+            expect_kind(lhs, Kind::Str, "cat")?;
+            stack.push(Kind::Str);
+            Ok(())
+        }
+        "len" => {
+            let value = pop_kind(stack, "len", "Str")?;
+            expect_kind(value, Kind::Str, "len")?;
+            stack.push(Kind::Num);
+            Ok(())
+        }
+        "str" => {
+            pop_kind(stack, "str", "a value")?;
+            stack.push(Kind::Str);
+            Ok(())
+        }
+        "def" => {
+            pop_kind(stack, "def", "a value")?;
+            let sym = pop_kind(stack, "def", "Sym")?;
+            expect_kind(sym, Kind::Sym, "def")?;
+            Ok(())
+        }
+        "require" | "import" => {
+            // モジュール名は Sym か Str のどちらでも渡せるため、Kind では種類を絞らない
+            pop_kind(stack, name, "Sym or Str")?;
+            Ok(())
+        }
+        _ => {
+            stack.push(Kind::Unknown);
+            Ok(())
+        }
+    }
+}
+
+// condブロックがちょうど1つのNumを積んで終わることを確認する (if/while/switch の条件共通)。
+fn expect_cond_result(cond_after: &[Kind], base: &[Kind], operator: &str) -> Result<(), TypeError> {
+    if cond_after.len() == base.len() + 1 && matches!(cond_after.last(), Some(Kind::Num | Kind::Unknown)) {
+        Ok(())
     } else {
-        for code in false_branch {
-            eval(code, vm);
+        Err(TypeError {
+            operator: operator.to_string(),
+            expected: "the condition block to leave exactly one Num".to_string(),
+            found: format!("{:?}", cond_after),
+        })
+    }
+}
+
+// switchの各ケースが同じスタック形状を残すことを確認しながら合流させる。
+fn merge_shapes(merged: &mut Option<Vec<Kind>>, candidate: Vec<Kind>, operator: &str) -> Result<(), TypeError> {
+    match merged {
+        None => {
+            *merged = Some(candidate);
+            Ok(())
+        }
+        Some(shape) if shapes_compatible(shape, &candidate) => Ok(()),
+        Some(shape) => Err(TypeError {
+            operator: operator.to_string(),
+            expected: format!("every case to leave {:?}", shape),
+            found: format!("{:?}", candidate),
+        }),
+    }
+}
+
+// if: cond/true/falseの3ブロック。trueとfalseは合流後に同じスタック形状でなければならない。
+fn check_if(cond: &[Value], true_branch: &[Value], false_branch: &[Value], base: Vec<Kind>) -> Result<Vec<Kind>, TypeError> {
+    let cond_after = check_block(cond, base.clone())?;
+    expect_cond_result(&cond_after, &base, "if")?;
+    let true_after = check_block(true_branch, base.clone())?;
+    let false_after = check_block(false_branch, base)?;
+    if !shapes_compatible(&true_after, &false_after) {
+        return Err(TypeError {
+            operator: "if".to_string(),
+            expected: "both branches to leave the same stack shape".to_string(),
+            found: format!("true -> {:?}, false -> {:?}", true_after, false_after),
+        });
+    }
+    Ok(true_after)
+}
+
+// while: ループ本体はスタックの深さ・種類を変えないこと (変数経由でのみ状態を持つ想定)。
+fn check_while(cond: &[Value], body: &[Value], base: Vec<Kind>) -> Result<Vec<Kind>, TypeError> {
+    let cond_after = check_block(cond, base.clone())?;
+    expect_cond_result(&cond_after, &base, "while")?;
+    let body_after = check_block(body, base.clone())?;
+    if !shapes_compatible(&body_after, &base) {
+        return Err(TypeError {
+            operator: "while".to_string(),
+            expected: "the loop body to leave the stack unchanged".to_string(),
+            found: format!("{:?}", body_after),
+        });
+    }
+    Ok(base)
+}
+
+// for: ループ本体にはインデックスが1つ積まれた状態で入り、それを消費してベースに戻ること。
+// トップレベルの for は start/end がすでにスタック上で Num と検証済みなので、本体だけを
+// ここで検査する（ネストした for の検査はこれに加えて start/end のブロックも検査する）。
+fn check_for_body(body: &[Value], base: Vec<Kind>) -> Result<Vec<Kind>, TypeError> {
+    let mut body_input = base.clone();
+    body_input.push(Kind::Num);
+    let body_after = check_block(body, body_input)?;
+    if !shapes_compatible(&body_after, &base) {
+        return Err(TypeError {
+            operator: "for".to_string(),
+            expected: "the loop body to leave the stack unchanged besides the pushed index".to_string(),
+            found: format!("{:?}", body_after),
+        });
+    }
+    Ok(base)
+}
+
+// for (ネスト): start/end はそれぞれ評価されてちょうど1つの Num を残すこと
+// （ループ先頭で1回だけ評価されるカウンタ・上限になる）。
+fn check_for(start: &[Value], end: &[Value], body: &[Value], base: Vec<Kind>) -> Result<Vec<Kind>, TypeError> {
+    let start_after = check_block(start, base.clone())?;
+    expect_cond_result(&start_after, &base, "for")?;
+    let end_after = check_block(end, base.clone())?;
+    expect_cond_result(&end_after, &base, "for")?;
+    check_for_body(body, base)
+}
+
+// switch: cond/bodyのペアが並び、最後にdefaultが続く。全ケース (default含む) が
+// 同じスタック形状で合流しなければならない。
+fn check_switch(cases: &[Value], base: Vec<Kind>) -> Result<Vec<Kind>, TypeError> {
+    let pair_count = switch_pair_count(cases.len()).ok_or_else(|| TypeError {
+        operator: "switch".to_string(),
+        expected: "an odd number of blocks (cond/body pairs followed by a default)".to_string(),
+        found: format!("{} block(s)", cases.len()),
+    })?;
+    let mut merged: Option<Vec<Kind>> = None;
+    for i in 0..pair_count {
+        let cond = expect_block(&cases[i * 2], "switch")?;
+        let body = expect_block(&cases[i * 2 + 1], "switch")?;
+        let cond_after = check_block(cond, base.clone())?;
+        expect_cond_result(&cond_after, &base, "switch")?;
+        let body_after = check_block(body, base.clone())?;
+        merge_shapes(&mut merged, body_after, "switch")?;
+    }
+    let default = expect_block(&cases[cases.len() - 1], "switch")?;
+    let default_after = check_block(default, base)?;
+    merge_shapes(&mut merged, default_after, "switch")?;
+    Ok(merged.unwrap())
+}
+
+// ブロック本体 (Vec<Value>) を compile_block と同じ構造でたどりながら、抽象スタックを
+// シミュレートする。if/while/for/switch のパターンは再帰的に check_if/check_while/
+// check_for/check_switch に委ねる。
+fn check_block(items: &[Value], mut stack: Vec<Kind>) -> Result<Vec<Kind>, TypeError> {
+    let mut i = 0;
+    while i < items.len() {
+        if let Some((shape, next)) = match_control_shape(items, i) {
+            // match_control_shape で Block であることを確認済みなので to_block は必ず成功する
+            stack = match shape {
+                ControlShape::If => {
+                    let cond = items[i].clone().to_block().unwrap();
+                    let true_branch = items[i + 1].clone().to_block().unwrap();
+                    let false_branch = items[i + 2].clone().to_block().unwrap();
+                    check_if(&cond, &true_branch, &false_branch, stack)?
+                }
+                ControlShape::While => {
+                    let cond = items[i].clone().to_block().unwrap();
+                    let body = items[i + 1].clone().to_block().unwrap();
+                    check_while(&cond, &body, stack)?
+                }
+                ControlShape::For => {
+                    let start = items[i].clone().to_block().unwrap();
+                    let end = items[i + 1].clone().to_block().unwrap();
+                    let body = items[i + 2].clone().to_block().unwrap();
+                    check_for(&start, &end, &body, stack)?
+                }
+                ControlShape::Switch => {
+                    let cases = items[i].clone().to_block().unwrap();
+                    check_switch(&cases, stack)?
+                }
+            };
+            i = next;
+            continue;
+        }
+        match &items[i] {
+            Value::Op(name) => check_op(name, &mut stack)?,
+            other => stack.push(Kind::from_value(other)),
+        }
+        i += 1;
+    }
+    Ok(stack)
+}
+
+fn check_if_top_level(vm: &Vm) -> Result<Vec<Kind>, TypeError> {
+    let len = vm.stack.len();
+    if len < 3 {
+        return Err(TypeError {
+            operator: "if".to_string(),
+            expected: "three blocks".to_string(),
+            found: format!("{} value(s) on the stack", len),
+        });
+    }
+    let cond = expect_block(&vm.stack[len - 3], "if")?;
+    let true_branch = expect_block(&vm.stack[len - 2], "if")?;
+    let false_branch = expect_block(&vm.stack[len - 1], "if")?;
+    let base: Vec<Kind> = vm.stack[..len - 3].iter().map(Kind::from_value).collect();
+    check_if(cond, true_branch, false_branch, base)
+}
+
+fn check_while_top_level(vm: &Vm) -> Result<Vec<Kind>, TypeError> {
+    let len = vm.stack.len();
+    if len < 2 {
+        return Err(TypeError {
+            operator: "while".to_string(),
+            expected: "two blocks".to_string(),
+            found: format!("{} value(s) on the stack", len),
+        });
+    }
+    let cond = expect_block(&vm.stack[len - 2], "while")?;
+    let body = expect_block(&vm.stack[len - 1], "while")?;
+    let base: Vec<Kind> = vm.stack[..len - 2].iter().map(Kind::from_value).collect();
+    check_while(cond, body, base)
+}
+
+fn check_for_top_level(vm: &Vm) -> Result<Vec<Kind>, TypeError> {
+    let len = vm.stack.len();
+    if len < 3 {
+        return Err(TypeError {
+            operator: "for".to_string(),
+            expected: "a start Num, an end Num and a block".to_string(),
+            found: format!("{} value(s) on the stack", len),
+        });
+    }
+    let start = Kind::from_value(&vm.stack[len - 3]);
+    expect_kind(start, Kind::Num, "for")?;
+    let end = Kind::from_value(&vm.stack[len - 2]);
+    expect_kind(end, Kind::Num, "for")?;
+    let body = expect_block(&vm.stack[len - 1], "for")?;
+    let base: Vec<Kind> = vm.stack[..len - 3].iter().map(Kind::from_value).collect();
+    check_for_body(body, base)
+}
+
+fn check_switch_top_level(vm: &Vm) -> Result<Vec<Kind>, TypeError> {
+    let len = vm.stack.len();
+    if len < 1 {
+        return Err(TypeError {
+            operator: "switch".to_string(),
+            expected: "one block".to_string(),
+            found: "0 value(s) on the stack".to_string(),
+        });
+    }
+    let cases = expect_block(&vm.stack[len - 1], "switch")?;
+    let base: Vec<Kind> = vm.stack[..len - 1].iter().map(Kind::from_value).collect();
+    check_switch(cases, base)
+}
+
+// evalから呼ばれるエントリポイント。今まさにトップレベルで評価しようとしているトークン1つを、
+// vm.stack の実際の中身から導いた抽象スタックに対して検証する。if/while/for/switch は
+// オペランドのブロックが vm.stack 上にすでに積まれているため、そこから覗き見て検証する。
+// def した関数の本体までは踏み込んで検証しないため、関数呼び出しの内部で起きる
+// スタック不足や型の不一致は、この型検査をすり抜けて VmError として実行時に現れる。
+fn check_top_level(value: &Value, vm: &Vm) -> Result<(), TypeError> {
+    match value {
+        Value::Op(name) if name == "if" => check_if_top_level(vm).map(|_| ()),
+        Value::Op(name) if name == "while" => check_while_top_level(vm).map(|_| ()),
+        Value::Op(name) if name == "for" => check_for_top_level(vm).map(|_| ()),
+        Value::Op(name) if name == "switch" => check_switch_top_level(vm).map(|_| ()),
+        Value::Op(name) => {
+            let mut stack: Vec<Kind> = vm.stack.iter().map(Kind::from_value).collect();
+            check_op(name, &mut stack)
         }
+        // リテラルは積むだけなので型エラーになりようがない
+        _ => Ok(()),
     }
 }
 
-// 変数定義を行う演算子を定義する関数
-fn op_def(vm: &mut Vm) {
-    let value = vm.stack.pop().unwrap();
-    eval(value, vm);
-    let value = vm.stack.pop().unwrap();
-    let sym = vm.stack.pop().unwrap().as_sym().to_string();
+// 変数定義を行う演算子を定義する関数。値がブロックであれば、その本体をバイトコードへ
+// コンパイルして vm.code の末尾に追記し（末尾は Ret で終える）、開始アドレスを
+// vm.functions に登録する。以降の呼び出しは Call(addr) 一発で済み、再帰呼び出しも
+// 明示的な call_stack を介して行われるのでネイティブスタックを消費しない。
+fn op_def(vm: &mut Vm) -> Result<(), VmError> {
+    let value = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+    let sym = vm.stack.pop().ok_or(VmError::StackUnderflow)?.as_sym()?.to_string();
 
-    vm.vars.insert(sym, value);
+    if let Value::Block(body) = value {
+        let addr = vm.code.len();
+        // 再帰呼び出し (自分自身を compile_block の中で Call できるように) に対応するため、
+        // 本体をコンパイルする前にアドレスを登録しておく。
+        vm.functions.insert(sym, addr);
+        let body_instrs = compile_block(&body, vm)?;
+        append_chunk(&mut vm.code, body_instrs);
+        vm.code.push(Instr::Ret);
+    } else {
+        vm.vars.insert(sym, value);
+    }
+    Ok(())
 }
 
-fn dup(vm: &mut Vm) {
-    let value = vm.stack.last().unwrap();
+fn dup(vm: &mut Vm) -> Result<(), VmError> {
+    let value = vm.stack.last().ok_or(VmError::StackUnderflow)?;
     vm.stack.push(value.clone());
+    Ok(())
 }
 
-fn exch(vm: &mut Vm) {
+fn exch(vm: &mut Vm) -> Result<(), VmError> {
     // [second, last] -> [last, second]
-    let last = vm.stack.pop().unwrap();
-    let second = vm.stack.pop().unwrap();
-    vm.stack.push(last); 
+    let last = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+    let second = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+    vm.stack.push(last);
     vm.stack.push(second);
+    Ok(())
 }
 
 // 値を標準出力に出力する関数
-fn puts(vm: &mut Vm) {
-    let value = vm.stack.pop().unwrap();
+fn puts(vm: &mut Vm) -> Result<(), VmError> {
+    let value = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
     println!("{}", value.to_string());
+    Ok(())
+}
+
+// 2つの文字列を連結する (Yard のバイトコードダンプにある cat 命令と同じ役割)
+fn cat(vm: &mut Vm) -> Result<(), VmError> {
+    let rhs = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+    let lhs = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+    vm.stack.push(Value::Str(format!("{}{}", lhs.as_str()?, rhs.as_str()?)));
+    Ok(())
+}
+
+// 文字列の長さを返す
+fn str_len(vm: &mut Vm) -> Result<(), VmError> {
+    let value = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+    vm.stack.push(Value::Num(value.as_str()?.len() as i32));
+    Ok(())
+}
+
+// 値を文字列へ変換する (Num は to_string で数値表記の文字列になる)
+fn to_str(vm: &mut Vm) -> Result<(), VmError> {
+    let value = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+    vm.stack.push(Value::Str(value.to_string()));
+    Ok(())
+}
+
+// 他の rustack ソースファイルを読み込み、現在の Vm 上で評価することで def された
+// ワードをそのまま利用可能にする（簡易的なモジュールシステム）。パスは require を
+// 呼び出したファイル自身のディレクトリ (vm.base_dir) からの相対として解決するため、
+// ネストした require も呼び出し元からの相対パスで素直に解決できる。
+// 既に読み込み済みの正規化パスは vm.loaded_modules に記録してあり、二重ロードや
+// 循環 import は黙ってスキップする。
+fn require(vm: &mut Vm) -> Result<(), VmError> {
+    let name_value = vm.stack.pop().ok_or(VmError::StackUnderflow)?;
+    let name = match &name_value {
+        Value::Sym(s) => s.clone(),
+        Value::Str(s) => s.clone(),
+        other => {
+            return Err(VmError::TypeMismatch {
+                expected: "Sym or Str".to_string(),
+                found: format!("{:?}", other),
+            })
+        }
+    };
+
+    let path = vm.base_dir.join(format!("{}.rustack", name));
+    let canonical_path = path
+        .canonicalize()
+        .map_err(|_| VmError::ModuleNotFound(path.display().to_string()))?;
+
+    if vm.loaded_modules.contains(&canonical_path) {
+        return Ok(());
+    }
+    vm.loaded_modules.insert(canonical_path.clone());
+
+    let content = std::fs::read_to_string(&canonical_path)
+        .map_err(|_| VmError::ModuleNotFound(path.display().to_string()))?;
+
+    // 読み込んだファイルの中でさらに require されるパスは、そのファイル自身からの
+    // 相対パスで解決されるべきなので、評価している間だけ base_dir を切り替える。
+    let module_dir = canonical_path
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_else(|| std::path::PathBuf::from("."));
+    let caller_base_dir = std::mem::replace(&mut vm.base_dir, module_dir);
+
+    let result = run_lines(std::io::Cursor::new(content), vm);
+
+    vm.base_dir = caller_base_dir;
+    result
 }
 
 // Vmの状態の差分を表示する関数
@@ -294,13 +1342,13 @@ fn debug_vm_diff(code: &str, before: &Vm, after: &Vm) {
 
 #[cfg(test)]
 mod test {
-    use super::{parse_batch, Value::*};
+    use super::{parse_batch, Value::*, VmError};
     use std::io::Cursor;
 
     #[test]
     fn test_group() {
         assert_eq!(
-            parse_batch(Cursor::new("1 2 + { 3 4 * }")),
+            parse_batch(Cursor::new("1 2 + { 3 4 * }")).unwrap(),
             vec![Num(3), Block(vec![Num(3), Num(4), Op("*".to_string())])]
         );
     }
@@ -308,7 +1356,7 @@ mod test {
     #[test]
     fn test_if_false() {
         assert_eq!(
-            parse_batch(Cursor::new("{ 0 } { 1 } { -1 } if")),
+            parse_batch(Cursor::new("{ 0 } { 1 } { -1 } if")).unwrap(),
              vec![Num(-1)]
         );
     }
@@ -316,7 +1364,7 @@ mod test {
     #[test]
     fn test_if_true() {
         assert_eq!(
-            parse_batch(Cursor::new("{ 1 } { 1 } { -1 } if")),
+            parse_batch(Cursor::new("{ 1 } { 1 } { -1 } if")).unwrap(),
              vec![Num(1)]
         );
     }
@@ -337,7 +1385,7 @@ if
         let cursor = Cursor::new(input.as_bytes());
 
         // 結果を確認
-        assert_eq!(parse_batch(cursor), vec![Num(10)]);
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(10)]);
     }
 
     #[test]
@@ -351,6 +1399,297 @@ if
         let cursor = Cursor::new(input.as_bytes());
 
         // 結果を確認
-        assert_eq!(parse_batch(cursor), vec![Num(20)]);
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(20)]);
+    }
+
+    #[test]
+    fn test_recursive_function() {
+        // def で定義した関数は Call/Ret を通じて実行されるため、再帰呼び出しでも
+        // ネイティブスタックを消費しないことを確認する。base case は引数を消費して
+        // 1 に置き換える必要があるが pop 相当の命令が無いため `0 * 1 +` で代用する
+        let input = r#"
+/fact {
+  { dup 1 < }
+  { 0 * 1 + }
+  { dup 1 - fact * }
+  if
+} def
+5 fact
+"#;
+        let cursor = Cursor::new(input.as_bytes());
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(120)]);
+    }
+
+    #[test]
+    fn test_while_loop() {
+        // while は再帰ではなく後方 Jump で回るので、深い反復でもネイティブスタックを
+        // 消費しない
+        let input = r#"
+/i 0 def
+/sum 0 def
+{ i 10 < }
+{
+  /sum sum i + def
+  /i i 1 + def
+}
+while
+sum
+"#;
+        let cursor = Cursor::new(input.as_bytes());
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(45)]);
+    }
+
+    #[test]
+    fn test_for_loop() {
+        // for は開始値から終了値未満まで現在の添字をスタックに積みながら body を実行する
+        let input = r#"
+/sum 0 def
+0 5 { sum + /sum exch def } for
+sum
+"#;
+        let cursor = Cursor::new(input.as_bytes());
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(10)]);
+    }
+
+    #[test]
+    fn test_for_loop_with_variable_bounds_inside_def_body() {
+        // def の本体 (= 静的にコンパイルされるネストした文脈) の中では for の
+        // start/end は { } で囲んだブロックとして書く必要がある（if/while/switch の
+        // 被演算子と同じ規約）。変数参照の境界が実行時に評価されて動くことを確認する。
+        let input = r#"
+/n 5 def
+/sum 0 def
+/loop { { 0 } { n } { sum + /sum exch def } for } def
+loop
+sum
+"#;
+        let cursor = Cursor::new(input.as_bytes());
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(10)]);
+    }
+
+    #[test]
+    fn test_for_loop_with_multi_token_bounds_inside_def_body() {
+        // start/end がブロック化されているおかげで、複数トークンからなる式
+        // (例: `n 1 +`) でも境界を1トークン目で打ち切ることなく正しく評価できる。
+        let input = r#"
+/n 3 def
+/sum 0 def
+/loop { { 0 } { n 1 + } { sum + /sum exch def } for } def
+loop
+sum
+"#;
+        let cursor = Cursor::new(input.as_bytes());
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(6)]);
+    }
+
+    #[test]
+    fn test_switch_matches_first_true_case() {
+        let input = r#"
+/x 2 def
+{
+  { x 1 < } { -1 }
+  { x 1 < x 1 + < } { 1 }
+  { 0 }
+}
+switch
+"#;
+        let cursor = Cursor::new(input.as_bytes());
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(1)]);
+    }
+
+    #[test]
+    fn test_switch_falls_back_to_default() {
+        let input = r#"
+/x 10 def
+{
+  { x 1 < } { -1 }
+  { x 2 < } { 1 }
+  { 0 }
+}
+switch
+"#;
+        let cursor = Cursor::new(input.as_bytes());
+        assert_eq!(parse_batch(cursor).unwrap(), vec![Num(0)]);
+    }
+
+    #[test]
+    fn test_string_literal_with_spaces() {
+        // トークナイザが引用符の中の空白を1トークンとしてまとめることを確認する
+        assert_eq!(
+            parse_batch(Cursor::new(r#""hello world""#)).unwrap(),
+            vec![Str("hello world".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_cat_len_str() {
+        assert_eq!(
+            parse_batch(Cursor::new(r#""foo" "bar" cat"#)).unwrap(),
+            vec![Str("foobar".to_string())]
+        );
+        assert_eq!(parse_batch(Cursor::new(r#""hello" len"#)).unwrap(), vec![Num(5)]);
+        assert_eq!(parse_batch(Cursor::new("42 str")).unwrap(), vec![Str("42".to_string())]);
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let input = "/x 3 def\n`x = ${ x 1 + }`";
+        assert_eq!(
+            parse_batch(Cursor::new(input)).unwrap(),
+            vec![Str("x = 4".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_type_error_blocks_bad_arithmetic() {
+        // `+` は2つの Num を要求するが、左側が Str のため静的型検査で弾かれ、
+        // `+` 自体は実行されずスタックはそのまま残る
+        assert_eq!(
+            parse_batch(Cursor::new(r#""foo" 1 +"#)).unwrap(),
+            vec![Str("foo".to_string()), Num(1)]
+        );
+    }
+
+    #[test]
+    fn test_type_error_blocks_mismatched_if_branches() {
+        // true 分岐は Num を、false 分岐は Str を残すため分岐の合流先の形が揃わず、
+        // 静的型検査で弾かれて if 自体は実行されない (3つのブロックがそのまま残る)
+        assert_eq!(
+            parse_batch(Cursor::new(r#"{ 1 } { 1 } { "x" } if"#)).unwrap(),
+            vec![
+                Block(vec![Num(1)]),
+                Block(vec![Num(1)]),
+                Block(vec![Str("x".to_string())]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_divide_by_zero_returns_err() {
+        // `/` は token_to_value がシンボル定義 (`/name`) と見分けられないためトークナイザ
+        // 経由では書けず、div ネイティブ演算自体を直接呼び出して検証する
+        let mut vm = super::Vm::new();
+        vm.stack.push(Num(1));
+        vm.stack.push(Num(0));
+        assert_eq!(super::div(&mut vm), Err(VmError::DivideByZero));
+    }
+
+    #[test]
+    fn test_stack_underflow_inside_function_body_returns_err() {
+        let input = r#"
+/broken { dup } def
+broken
+"#;
+        assert_eq!(parse_batch(Cursor::new(input)), Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_undefined_word_inside_function_body_returns_err() {
+        let input = r#"
+/broken { not_defined } def
+broken
+"#;
+        assert_eq!(
+            parse_batch(Cursor::new(input)),
+            Err(VmError::UndefinedWord("not_defined".to_string()))
+        );
+    }
+
+    // require はファイルシステム上のモジュールを読むため、一時ディレクトリに
+    // .rustack ファイルを書き出してから Vm を直接操作して検証する。
+    fn test_module_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rustack_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_require_loads_def_from_sibling_file() {
+        let dir = test_module_dir("require_basic");
+        std::fs::write(dir.join("util.rustack"), "/double { 2 * } def").unwrap();
+
+        let mut vm = super::Vm::new();
+        vm.base_dir = dir.clone();
+        super::run_lines(Cursor::new("/util require\n10 double"), &mut vm).unwrap();
+
+        assert_eq!(vm.stack, vec![Num(20)]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_require_resolves_nested_import_relative_to_importer() {
+        let dir = test_module_dir("require_nested");
+        let sub_dir = dir.join("sub");
+        std::fs::create_dir_all(&sub_dir).unwrap();
+        // main_module.rustack から見た相対パス (sub/helper) で sub/helper.rustack を読み込む
+        std::fs::write(sub_dir.join("helper.rustack"), "/triple { 3 * } def").unwrap();
+        std::fs::write(
+            dir.join("main_module.rustack"),
+            "/sub/helper require",
+        )
+        .unwrap();
+
+        let mut vm = super::Vm::new();
+        vm.base_dir = dir.clone();
+        super::run_lines(Cursor::new("/main_module require\n4 triple"), &mut vm).unwrap();
+
+        assert_eq!(vm.stack, vec![Num(12)]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_require_same_module_twice_does_not_redefine() {
+        let dir = test_module_dir("require_twice");
+        // 読み込まれるたびに 1 を積むので、2回目の require で再評価されれば 1 が2つ残る
+        std::fs::write(dir.join("util.rustack"), "/double { 2 * } def\n1").unwrap();
+
+        let mut vm = super::Vm::new();
+        vm.base_dir = dir.clone();
+        super::run_lines(
+            Cursor::new("/util require\n/util require\n5 double"),
+            &mut vm,
+        )
+        .unwrap();
+
+        assert_eq!(vm.stack, vec![Num(1), Num(10)]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_require_inside_function_body_does_not_desync_ip() {
+        // require は内部で自前の run_appended/run_lines を回して vm.ip を使い回すため、
+        // def の本体のように呼び出し元の run_appended がまだ途中の命令列を実行している
+        // 最中に呼ばれると、戻ってきた後の命令（ここでは `1 +` と Ret）が silently
+        // スキップされてしまう不具合があった。そのリグレッションを防ぐ。
+        let dir = test_module_dir("require_inside_def_body");
+        std::fs::write(dir.join("util.rustack"), "/double { 2 * } def").unwrap();
+
+        let mut vm = super::Vm::new();
+        vm.base_dir = dir.clone();
+        super::run_lines(
+            Cursor::new("/loadIt { /util require 1 + } def\n0 loadIt"),
+            &mut vm,
+        )
+        .unwrap();
+
+        assert_eq!(vm.stack, vec![Num(1)]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_require_missing_module_returns_err() {
+        let dir = test_module_dir("require_missing");
+
+        let mut vm = super::Vm::new();
+        vm.base_dir = dir.clone();
+        assert!(matches!(
+            super::run_lines(Cursor::new("/does_not_exist require"), &mut vm),
+            Err(VmError::ModuleNotFound(_))
+        ));
+        std::fs::remove_dir_all(&dir).ok();
     }
 }